@@ -1,10 +1,16 @@
 extern crate csv;
 
 use crate::detections::rule::AggResult;
+use lazy_static::lazy_static;
 use serde_json::Value;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Instant;
-use tokio::{runtime::Runtime, spawn, task::JoinHandle};
+use tokio::sync::{mpsc, Mutex as AsyncMutex};
+use tokio::{runtime::Runtime, spawn};
 
 use crate::detections::configs;
 use crate::detections::print::AlertMessage;
@@ -13,12 +19,302 @@ use crate::detections::rule;
 use crate::detections::rule::RuleNode;
 use crate::detections::utils::get_serde_number_to_string;
 use crate::filter;
+use crate::options::threat_intel;
 use crate::yaml::ParseYaml;
 
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+
+use notify::{RecursiveMode, Watcher};
 
 const DIRPATH_RULES: &str = "rules";
 
+// 同一クラスタと見なすJaccard類似度の閾値。これ以上ならば既存クラスタへ割り当てる。
+const CLUSTER_SIMILARITY_THRESHOLD: f64 = 0.7;
+// セントロイドが保持するトークン数の上限。和集合で際限なく肥大化しないように打ち切る。
+const CLUSTER_CENTROID_MAX_TOKENS: usize = 256;
+
+lazy_static! {
+    // 検知をストリーミングでクラスタリングするためのグローバルな状態。
+    // insert_messageから検知が追加されるたびに更新され、print_unique_resultsでサマリを出力する。
+    pub static ref ALERT_CLUSTERS: std::sync::Mutex<AlertClusterer> =
+        std::sync::Mutex::new(AlertClusterer::new(CLUSTER_SIMILARITY_THRESHOLD));
+}
+
+/// 1クラスタ分の状態。代表タイトル・メンバー数・関与ホスト・時間範囲と、トークンのセントロイドを保持する。
+struct AlertCluster {
+    // 最初にこのクラスタを作った検知のタイトル(代表タイトルとして表示する)。
+    title: String,
+    // クラスタに属する検知の件数。
+    member_count: usize,
+    // 関与したホスト(Computer)の集合。
+    hosts: HashSet<String>,
+    // クラスタ内の最古・最新のタイムスタンプ(ISO8601文字列を辞書順で比較する)。
+    earliest: String,
+    latest: String,
+    // セントロイド(このクラスタを代表するトークン集合)。新規メンバーのトークンと和集合して更新する。
+    centroid: HashSet<String>,
+}
+
+/// ストリーミングなトークン集合クラスタリング器。
+/// 各検知から特徴シグネチャ(ルールID・EventID・Computer・正規化したコマンド/出力のトークン)を作り、
+/// 既存セントロイドとのJaccard類似度が閾値以上なら同じクラスタへ、そうでなければ新規クラスタを作る。
+pub struct AlertClusterer {
+    clusters: Vec<AlertCluster>,
+    threshold: f64,
+}
+
+impl AlertClusterer {
+    pub fn new(threshold: f64) -> AlertClusterer {
+        AlertClusterer {
+            clusters: vec![],
+            threshold,
+        }
+    }
+
+    /// 検知1件をクラスタへ取り込む。最も類似したクラスタが閾値を満たせばそこへ、なければ新規クラスタを作る。
+    pub fn add(
+        &mut self,
+        title: &str,
+        event_id: &str,
+        computer: &str,
+        text: &str,
+        timestamp: &str,
+    ) {
+        let tokens = AlertClusterer::signature(title, event_id, computer, text);
+
+        // 既存クラスタの中で最もJaccard類似度が高いものを探す。
+        let mut best_idx = None;
+        let mut best_sim = self.threshold;
+        for (idx, cluster) in self.clusters.iter().enumerate() {
+            let sim = jaccard(&tokens, &cluster.centroid);
+            if sim >= best_sim {
+                best_sim = sim;
+                best_idx = Some(idx);
+            }
+        }
+
+        match best_idx {
+            Some(idx) => {
+                let cluster = &mut self.clusters[idx];
+                cluster.member_count += 1;
+                cluster.hosts.insert(computer.to_owned());
+                update_time_range(cluster, timestamp);
+                // セントロイドを和集合で更新しつつ、上限を超えたら打ち切ってメモリを抑える。
+                for token in tokens {
+                    if cluster.centroid.len() >= CLUSTER_CENTROID_MAX_TOKENS {
+                        break;
+                    }
+                    cluster.centroid.insert(token);
+                }
+            }
+            None => {
+                let mut hosts = HashSet::new();
+                hosts.insert(computer.to_owned());
+                self.clusters.push(AlertCluster {
+                    title: title.to_owned(),
+                    member_count: 1,
+                    hosts,
+                    earliest: timestamp.to_owned(),
+                    latest: timestamp.to_owned(),
+                    centroid: tokens,
+                });
+            }
+        }
+    }
+
+    /// クラスタ状態を空に戻す。スキャン(Detection)単位で状態を作り直し、連続/複数回スキャンで
+    /// 古いクラスタが際限なく溜まらないようにするために使う。
+    pub fn reset(&mut self) {
+        self.clusters.clear();
+    }
+
+    /// クラスタ毎のサマリ(代表タイトル・件数・ホスト数・時間範囲)を出力する。
+    fn print_summaries(&self) {
+        if self.clusters.is_empty() {
+            return;
+        }
+        println!();
+        println!("Clustered alerts: {}", self.clusters.len());
+        for cluster in &self.clusters {
+            println!(
+                "  [{} events, {} hosts, {} ~ {}] {}",
+                cluster.member_count,
+                cluster.hosts.len(),
+                cluster.earliest,
+                cluster.latest,
+                cluster.title,
+            );
+        }
+    }
+
+    // 特徴シグネチャを作る。ルールID相当のタイトル・EventID・Computerを接頭辞付きトークンとして入れ、
+    // コマンド/出力文字列は正規化して単語トークンに分割する。
+    fn signature(title: &str, event_id: &str, computer: &str, text: &str) -> HashSet<String> {
+        let mut tokens = HashSet::new();
+        tokens.insert(format!("rule:{title}"));
+        tokens.insert(format!("eid:{event_id}"));
+        tokens.insert(format!("host:{computer}"));
+        for token in tokenize(text) {
+            tokens.insert(token);
+        }
+        return tokens;
+    }
+}
+
+// 文字列を正規化して単語トークンの集合に分割する。英数字以外で区切り、小文字化し、空トークンは捨てる。
+fn tokenize(text: &str) -> HashSet<String> {
+    return text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .collect();
+}
+
+// 2つのトークン集合のJaccard類似度 |A∩B| / |A∪B| を返す。両方空の場合は0とする。
+fn jaccard(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 0.0;
+    }
+    let intersection = a.intersection(b).count() as f64;
+    let union = (a.len() + b.len()) as f64 - intersection;
+    if union == 0.0 {
+        return 0.0;
+    }
+    return intersection / union;
+}
+
+// タイムスタンプ文字列(ISO8601想定)を辞書順比較でクラスタの時間範囲に反映する。
+fn update_time_range(cluster: &mut AlertCluster, timestamp: &str) {
+    if timestamp.is_empty() {
+        return;
+    }
+    if cluster.earliest.is_empty() || timestamp < cluster.earliest.as_str() {
+        cluster.earliest = timestamp.to_owned();
+    }
+    if cluster.latest.is_empty() || timestamp > cluster.latest.as_str() {
+        cluster.latest = timestamp.to_owned();
+    }
+}
+
+// 1バッチあたりに含めるレコード数。大きすぎるとキャンセル・進捗の粒度が粗くなり、小さすぎるとオーバーヘッドが増える。
+const RECORD_BATCH_SIZE: usize = 1000;
+// --resumeで読み書きするチェックポイントファイル。ルール毎に最後まで処理したレコードのオフセットを記録する。
+const CHECKPOINT_FILEPATH: &str = ".hayabusa_checkpoint";
+// 進捗イベントを出力する間隔。
+const PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// ルール実行ジョブの進捗・キャンセル・再開を制御するためのオプション。
+/// execute_rulesの呼び出し元から共有状態を差し込めるようにしている。
+pub struct JobOptions {
+    // 前回のチェックポイントから再開する場合はtrue。
+    pub resume: bool,
+    // チェックポイントファイルを書き出すかどうか。通常スキャンでは副作用を残さないよう既定でfalseとし、
+    // --resumeワークフローを使うときだけ有効にする。
+    pub checkpoint: bool,
+    // バッチの境界で確認される共有キャンセルフラグ。trueになると各ワーカーは速やかに処理を打ち切る。
+    pub cancel: Arc<AtomicBool>,
+    // 指定された場合、スキャン中にrules/ディレクトリを監視して稼働中のルールセットをホットリロードする。
+    pub watch: Option<WatchOptions>,
+}
+
+impl Default for JobOptions {
+    fn default() -> JobOptions {
+        JobOptions {
+            resume: false,
+            checkpoint: false,
+            cancel: Arc::new(AtomicBool::new(false)),
+            watch: None,
+        }
+    }
+}
+
+impl JobOptions {
+    /// プロセス引数からジョブオプションを組み立てる。`--resume`が指定されていればチェックポイントからの再開と
+    /// チェックポイントの書き出しを有効にする。通常スキャン(フラグ無し)では副作用を残さない既定値になる。
+    pub fn from_args() -> JobOptions {
+        let args: Vec<String> = std::env::args().collect();
+        let resume = args.iter().any(|arg| arg == "--resume");
+        // --watchが指定されていれば、rules/を監視してルールセットをホットリロードする。
+        let watch = if args.iter().any(|arg| arg == "--watch") {
+            Some(WatchOptions {
+                level: "informational".to_owned(),
+                rulespath: None,
+                exclude_ids: Arc::new(filter::exclude_ids()),
+            })
+        } else {
+            None
+        };
+        JobOptions {
+            resume,
+            checkpoint: resume,
+            watch,
+            ..JobOptions::default()
+        }
+    }
+}
+
+/// watchモードでルールを再読込するために必要な設定。起動時のparse_rule_filesと同じ引数を引き回す。
+pub struct WatchOptions {
+    pub level: String,
+    pub rulespath: Option<String>,
+    pub exclude_ids: Arc<filter::RuleExclude>,
+}
+
+/// 「最後まで処理したレコードのオフセット」を (evtx_filepath, rulepath) 毎に保持するチェックポイント。
+/// evtxファイルを跨いでも意味のあるオフセットになるよう、ファイル単位でそのルールの処理済み件数を記録する。
+/// 再開時はファイル毎に記録された件数より前のレコードをスキップする。
+#[derive(Default)]
+struct Checkpoint {
+    // key: (evtx_filepath, rulepath), value: そのファイルでこのルールが処理済みのレコード数。
+    offsets: HashMap<(String, String), usize>,
+}
+
+impl Checkpoint {
+    // resumeが指定された場合のみ既存のチェックポイントファイルを読み込む。無ければ空のまま返す。
+    fn load(resume: bool) -> Checkpoint {
+        let mut checkpoint = Checkpoint::default();
+        if !resume {
+            return checkpoint;
+        }
+        let file = match File::open(CHECKPOINT_FILEPATH) {
+            Ok(file) => file,
+            Err(_) => return checkpoint,
+        };
+        for line in BufReader::new(file).lines().flatten() {
+            checkpoint.ingest(&line);
+        }
+        return checkpoint;
+    }
+
+    // 1行("evtx_filepath\trulepath\toffset"形式)を取り込む。形式が合わない行は無視する。
+    fn ingest(&mut self, line: &str) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() == 3 {
+            if let Ok(offset) = fields[2].parse::<usize>() {
+                self.offsets
+                    .insert((fields[0].to_string(), fields[1].to_string()), offset);
+            }
+        }
+    }
+
+    fn offset_of(&self, filepath: &str, rulepath: &str) -> usize {
+        return *self
+            .offsets
+            .get(&(filepath.to_string(), rulepath.to_string()))
+            .unwrap_or(&0);
+    }
+}
+
+/// チェックポイントファイルを現在の全オフセットで書き直す。
+/// 追記ではなく毎回丸ごと書き直すことで、1回のスキャンでファイルが (file, rule) の組数を超えて肥大化しないようにする。
+fn persist_checkpoint(path: &PathBuf, offsets: &HashMap<(String, String), usize>) {
+    if let Ok(mut file) = File::create(path) {
+        for ((filepath, rulepath), offset) in offsets {
+            writeln!(file, "{}\t{}\t{}", filepath, rulepath, offset).ok();
+        }
+    }
+}
+
 // イベントファイルの1レコード分の情報を保持する構造体
 #[derive(Clone, Debug)]
 pub struct EvtxRecordInfo {
@@ -39,16 +335,56 @@ impl EvtxRecordInfo {
 
 #[derive(Debug)]
 pub struct Detection {
-    pub rules: Vec<RuleNode>,
+    // 稼働中のルールセット。watchモードでのホットリロードがそのまま実行側に反映されるよう、
+    // 共有のRwLock越しに保持する。execute_rulesはここから取り出して実行し、完了後に書き戻す。
+    pub rules: Arc<RwLock<Vec<RuleNode>>>,
+    // watcherがルールを再読込するたびに増える世代カウンタ。スキャン中に再読込が起きたかの判定に使う。
+    reload_generation: Arc<AtomicUsize>,
 }
 
 impl Detection {
     pub fn new(rules: Vec<RuleNode>) -> Detection {
-        return Detection { rules: rules };
+        // 起動時に脅威インテリDBを有効化する。フィードが無ければ空のまま(通常スキャンには影響しない)。
+        threat_intel::install_default();
+        // クラスタ状態はスキャン単位で作り直す。前回のスキャンのクラスタが残らないようここで空にする。
+        ALERT_CLUSTERS.lock().unwrap().reset();
+        return Detection {
+            rules: Arc::new(RwLock::new(rules)),
+            reload_generation: Arc::new(AtomicUsize::new(0)),
+        };
     }
 
     pub fn start(self, rt: &Runtime, records: Vec<EvtxRecordInfo>) -> Self {
-        return rt.block_on(self.execute_rules(records));
+        return self.start_with_options(rt, records, JobOptions::from_args());
+    }
+
+    /// 進捗・キャンセル・再開を制御するジョブオプションを指定して実行する。
+    pub fn start_with_options(
+        self,
+        rt: &Runtime,
+        records: Vec<EvtxRecordInfo>,
+        opts: JobOptions,
+    ) -> Self {
+        // watchモードが指定されていれば、稼働中のルールセット(self.rules)を監視対象にしてホットリロードする。
+        // 返されるWatcherはスキャンが終わるまで生存させ続ける(dropすると監視が止まる)。
+        let _watcher = opts.watch.as_ref().and_then(|w| {
+            match self.watch_rule_files(
+                w.level.clone(),
+                w.rulespath.clone(),
+                Arc::clone(&w.exclude_ids),
+            ) {
+                Ok(watcher) => Some(watcher),
+                Err(e) => {
+                    AlertMessage::warn(
+                        &mut std::io::stdout().lock(),
+                        format!("Failed to start rule watcher: {e}"),
+                    )
+                    .ok();
+                    None
+                }
+            }
+        });
+        return rt.block_on(self.execute_rules(records, opts));
     }
 
     // ルールファイルをパースします。
@@ -105,39 +441,178 @@ impl Detection {
         return ret;
     }
 
-    // 複数のイベントレコードに対して、複数のルールを1個実行します。
-    async fn execute_rules(mut self, records: Vec<EvtxRecordInfo>) -> Self {
+    // rules/ ディレクトリを監視し、ファイルの追加・変更・削除を検知したら
+    // YAMLパースとRuleNode::init()のパイプラインを再実行して、検証済みのルールセットをArc<RwLock<_>>越しに差し替えます。
+    // watch モードでアナリストが稼働中のプロセスに対して検知ルールを更新できるようにするためのもの。
+    // 不正なルールはAlertMessage::warnで警告を出したうえでスキップされ、既存の正常なルールセットは壊されません。
+    // 再読込のたびにparse_rule_files経由でprint_rule_load_infoが呼ばれるため、起動時と同じ読込サマリが表示されます。
+    // 除外IDフィルタは監視しない。再読込には呼び出し時に渡されたexclude_idsをそのまま使うため、フィルタの変更は反映されない。
+    // 戻り値のWatcherは生存させ続ける必要がある(drop すると監視が止まる)。
+    pub fn watch_rule_files(
+        &self,
+        level: String,
+        rulespath: Option<String>,
+        exclude_ids: Arc<filter::RuleExclude>,
+    ) -> notify::Result<notify::RecommendedWatcher> {
+        // 稼働中のDetectionのルールセットを共有して差し替える。再読込はそのまま実行側に反映される。
+        let rules = Arc::clone(&self.rules);
+        let generation = Arc::clone(&self.reload_generation);
+        let watch_path = PathBuf::from(rulespath.clone().unwrap_or_else(|| DIRPATH_RULES.to_owned()));
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+                let event = match res {
+                    Ok(event) => event,
+                    Err(e) => {
+                        AlertMessage::warn(
+                            &mut std::io::stdout().lock(),
+                            format!("Rule watch error: {e}"),
+                        )
+                        .ok();
+                        return;
+                    }
+                };
+                // 追加・変更・削除以外(アクセス等)のイベントは無視する。
+                use notify::EventKind;
+                if !matches!(
+                    event.kind,
+                    EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)
+                ) {
+                    return;
+                }
+                // ルールを再読込する。1件も読み込めなかった場合は既存のセットを保持する。
+                let reloaded =
+                    Detection::parse_rule_files(level.clone(), rulespath.as_deref(), &exclude_ids);
+                if reloaded.is_empty() {
+                    AlertMessage::warn(
+                        &mut std::io::stdout().lock(),
+                        "No valid rules after reload. Keeping the previous rule set.".to_string(),
+                    )
+                    .ok();
+                    return;
+                }
+                if let Ok(mut guard) = rules.write() {
+                    *guard = reloaded;
+                }
+                // 再読込が起きたことを世代カウンタで知らせる。実行側はこれを見て、
+                // スキャン中に更新されたルールセットを実行後に上書きしないようにする。
+                generation.fetch_add(1, Ordering::Relaxed);
+            })?;
+        watcher.watch(&watch_path, RecursiveMode::Recursive)?;
+        return Ok(watcher);
+    }
+
+    // 複数のイベントレコードに対して、複数のルールを実行します。
+    // ルールを1個の作業単位としてMPSCチャネルに投入し、ワーカープールが取り合う(work-stealing)ことで、
+    // 速いルールが遅いルールの完了を待って遊ばないようにしている。バッチ境界でキャンセルの確認と
+    // 進捗カウンタの更新を行い、チェックポイントはルール1個の実行が終わるたびに更新する。
+    async fn execute_rules(mut self, records: Vec<EvtxRecordInfo>, opts: JobOptions) -> Self {
         let records_arc = Arc::new(records);
-        // // 各rule毎にスレッドを作成して、スレッドを起動する。
-        let rules = self.rules;
-        let handles: Vec<JoinHandle<RuleNode>> = rules
-            .into_iter()
-            .map(|rule| {
-                let records_cloned = Arc::clone(&records_arc);
-                return spawn(async move {
-                    let moved_rule = Detection::execute_rule(rule, records_cloned);
-                    return moved_rule;
-                });
-            })
-            .collect();
+        let total_records = records_arc.len();
+        let processed = Arc::new(AtomicUsize::new(0));
+        let checkpoint = Arc::new(Checkpoint::load(opts.resume));
+        let checkpoint_path = PathBuf::from(CHECKPOINT_FILEPATH);
+        // 書き出し中のオフセット。(file, rule)毎の最新値をここに集約し、ルール1個の走査が終わるたびにファイルを丸ごと書き直す。
+        let live_offsets = Arc::new(std::sync::Mutex::new(HashMap::new()));
+        let checkpoint_enabled = opts.checkpoint || opts.resume;
+
+        // Ctrl-Cでキャンセルフラグを立て、バッチ境界でワーカーが速やかに処理を打ち切れるようにする。
+        let cancel_signal = Arc::clone(&opts.cancel);
+        spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                cancel_signal.store(true, Ordering::Relaxed);
+            }
+        });
+
+        // 各ルールを作業単位としてチャネルに流し込む。受信側をワーカー間で共有してwork-stealingを実現する。
+        // 共有RwLockから実行対象のルールを取り出す(watch側が再読込していれば最新のセットを使う)。
+        let start_generation = self.reload_generation.load(Ordering::Relaxed);
+        let rules = std::mem::take(&mut *self.rules.write().unwrap());
+        let rule_total = rules.len();
+        let (rule_tx, rule_rx) = mpsc::unbounded_channel::<RuleNode>();
+        for rule in rules {
+            rule_tx.send(rule).ok();
+        }
+        drop(rule_tx);
+        let rule_rx = Arc::new(AsyncMutex::new(rule_rx));
+        let (result_tx, mut result_rx) = mpsc::unbounded_channel::<RuleNode>();
 
-        // 全スレッドの実行完了を待機
+        // CPUコア数を上限にワーカータスクを起動する。ルール数が少ない場合はその数に合わせる。
+        let worker_count = std::cmp::min(rule_total.max(1), num_cpus::get().max(1));
+        for _ in 0..worker_count {
+            let rule_rx = Arc::clone(&rule_rx);
+            let result_tx = result_tx.clone();
+            let records_cloned = Arc::clone(&records_arc);
+            let processed = Arc::clone(&processed);
+            let checkpoint = Arc::clone(&checkpoint);
+            let cancel = Arc::clone(&opts.cancel);
+            let checkpoint_path = checkpoint_path.clone();
+            let live_offsets = Arc::clone(&live_offsets);
+            spawn(async move {
+                loop {
+                    // 次の作業単位(ルール)を取り出す。空になったらワーカーを終了する。
+                    let rule = {
+                        let mut rx = rule_rx.lock().await;
+                        rx.recv().await
+                    };
+                    let rule = match rule {
+                        Some(rule) => rule,
+                        None => break,
+                    };
+                    let rule = Detection::execute_rule(
+                        rule,
+                        &records_cloned,
+                        &processed,
+                        &cancel,
+                        &checkpoint,
+                        &checkpoint_path,
+                        &live_offsets,
+                        checkpoint_enabled,
+                    );
+                    result_tx.send(rule).ok();
+                }
+            });
+        }
+        drop(result_tx);
+
+        // 進捗報告タスク。一定間隔で「処理済み / 総数」を出力する。全ワーカーの完了後に停止する。
+        let progress_processed = Arc::clone(&processed);
+        let progress_cancel = Arc::clone(&opts.cancel);
+        let progress_done = Arc::new(AtomicBool::new(false));
+        let progress_done_cloned = Arc::clone(&progress_done);
+        // 進捗の分母は「レコード数 × ルール数」。processedは各ルールが走査したレコードを足し込むため、
+        // 単なるtotal_recordsと比べると100%を超えてしまう。総作業量で割って正しい分数を表示する。
+        let total_work = total_records * rule_total.max(1);
+        let progress_handle = spawn(async move {
+            while !progress_done_cloned.load(Ordering::Relaxed) {
+                tokio::time::sleep(PROGRESS_INTERVAL).await;
+                let done = progress_processed.load(Ordering::Relaxed);
+                if progress_cancel.load(Ordering::Relaxed) {
+                    println!("Cancellation requested. Processed {done} records.");
+                    break;
+                }
+                println!("Scanning records: {done} / {total_work}");
+            }
+        });
+
+        // 全ワーカーの結果(実行済みルール)を回収する。
         let mut rules = vec![];
-        for handle in handles {
-            let ret_rule = handle.await.unwrap();
-            rules.push(ret_rule);
+        while let Some(rule) = result_rx.recv().await {
+            rules.push(rule);
         }
+        progress_done.store(true, Ordering::Relaxed);
+        progress_handle.await.ok();
 
-        // この関数の先頭でrules.into_iter()を呼び出している。それにより所有権がmapのruleを経由し、execute_ruleの引数に渡しているruleに移っているので、self.rulesには所有権が無くなっている。
-        // 所有権を失ったメンバー変数を持つオブジェクトをreturnするコードを書くと、コンパイラが怒になるので(E0382という番号のコンパイルエラー)、ここでself.rulesに所有権を戻している。
-        // self.rulesが再度所有権を取り戻せるように、Detection::execute_ruleで引数に渡したruleを戻り値として返すようにしている。
-        self.rules = rules;
+        // スキャン中にwatcherがルールを再読込していなければ、実行済みのルール(検知状態を持つ)を書き戻す。
+        // 再読込が起きていた場合は、RwLockにある新しいルールセットを次のスキャンのために温存し、上書きしない。
+        if self.reload_generation.load(Ordering::Relaxed) == start_generation {
+            *self.rules.write().unwrap() = rules;
+        }
 
         return self;
     }
 
     pub fn add_aggcondtion_msg(&self) {
-        for rule in &self.rules {
+        for rule in self.rules.read().unwrap().iter() {
             if !rule.has_agg_condition() {
                 continue;
             }
@@ -150,7 +625,8 @@ impl Detection {
     }
 
     pub fn print_unique_results(&self) {
-        let rules = &self.rules;
+        let rules_guard = self.rules.read().unwrap();
+        let rules = &*rules_guard;
         let levellabel = Vec::from([
             "Critical",
             "High",
@@ -182,22 +658,64 @@ impl Detection {
             total_unique += value;
         }
         println!("Unique alerts detected: {}", total_unique);
+
+        // 近似重複した検知をまとめたクラスタのサマリを出力し、挙動単位でのトリアージを助ける。
+        ALERT_CLUSTERS.lock().unwrap().print_summaries();
     }
 
     // 複数のイベントレコードに対して、ルールを1個実行します。
-    fn execute_rule(mut rule: RuleNode, records: Arc<Vec<EvtxRecordInfo>>) -> RuleNode {
+    // レコードはバッチに区切って処理し、バッチ境界でキャンセルの確認・進捗カウンタの更新を行う。
+    // チェックポイントはルール1個分の走査を終えた時点で一度だけ書き出す。
+    // 再開時はチェックポイントに記録された件数までのレコードをファイル毎にスキップする。
+    #[allow(clippy::too_many_arguments)]
+    fn execute_rule(
+        mut rule: RuleNode,
+        records: &[EvtxRecordInfo],
+        processed: &AtomicUsize,
+        cancel: &AtomicBool,
+        checkpoint: &Checkpoint,
+        checkpoint_path: &PathBuf,
+        live_offsets: &std::sync::Mutex<HashMap<(String, String), usize>>,
+        checkpoint_enabled: bool,
+    ) -> RuleNode {
         let start = Instant::now();
-        let records = &*records;
         let agg_condition = rule.has_agg_condition();
-        for record_info in records {
-            let result = rule.select(&record_info.evtx_filepath, &record_info);
-            if !result {
-                continue;
+        // evtxファイル毎に、このルールで何件目まで見たかを数える。再開時の判定はこのファイル単位の件数で行う。
+        let mut seen: HashMap<String, usize> = HashMap::new();
+        for batch in records.chunks(RECORD_BATCH_SIZE) {
+            // バッチ境界でキャンセル要求を確認する。要求されていれば残りのレコードは処理しない。
+            if cancel.load(Ordering::Relaxed) {
+                break;
+            }
+            for record_info in batch {
+                let count = seen.entry(record_info.evtx_filepath.clone()).or_insert(0);
+                *count += 1;
+                // 再開時: この(ファイル, ルール)で既に処理済みの範囲はスキップする。
+                if *count <= checkpoint.offset_of(&record_info.evtx_filepath, &rule.rulepath) {
+                    continue;
+                }
+                let result = rule.select(&record_info.evtx_filepath, record_info);
+                if !result {
+                    continue;
+                }
+                // aggregation conditionが存在しない場合はそのまま出力対応を行う
+                if !agg_condition {
+                    Detection::insert_message(&rule, record_info);
+                }
             }
-            // aggregation conditionが存在しない場合はそのまま出力対応を行う
-            if !agg_condition {
-                Detection::insert_message(&rule, &record_info);
+            // 進捗の分母は (レコード数 × ルール数)。スキップ分も含め走査済みとして数え、
+            // --resume時でも進捗が100%に到達するようにする。
+            processed.fetch_add(batch.len(), Ordering::Relaxed);
+        }
+
+        // チェックポイントはルール1個分を走査し終えた時点で一度だけ書き出す。
+        // バッチ毎にファイル全体を書き直すと O(バッチ数 × ルール数) 回の書き換えになるため、ルール単位に抑える。
+        if checkpoint_enabled {
+            let mut offsets = live_offsets.lock().unwrap();
+            for (filepath, count) in &seen {
+                offsets.insert((filepath.clone(), rule.rulepath.clone()), *count);
             }
+            persist_checkpoint(checkpoint_path, &offsets);
         }
 
         rule.duration += start.elapsed();
@@ -206,19 +724,44 @@ impl Detection {
 
     /// 条件に合致したレコードを表示するための関数
     fn insert_message(rule: &RuleNode, record_info: &EvtxRecordInfo) {
+        // レコード本文を脅威インテリDBに照合し、一致したIOCのラベルを出力文字列に付与する。
+        // DBが未ロードの場合は空を返すため、通常スキャンでは出力は変化しない。
+        let mut output = rule.yaml["output"].as_str().unwrap_or("").to_string();
+        let threat_labels = threat_intel::labels_for_text(&record_info.data_string);
+        if !threat_labels.is_empty() {
+            output.push_str(&format!(" ‖ ThreatIntel: {}", threat_labels.join(", ")));
+        }
+
+        let computer = record_info.record["Event"]["System"]["Computer"]
+            .to_string()
+            .replace("\"", "");
+        let event_id =
+            get_serde_number_to_string(&record_info.record["Event"]["System"]["EventID"])
+                .unwrap_or("-".to_owned());
+
+        // 似通った検知を1エントリへまとめてアラート疲れを抑えるため、ストリーミングのクラスタリングへ投入する。
+        let timestamp = record_info.record["Event"]["System"]["TimeCreated"]["#attributes"]
+            ["SystemTime"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+        ALERT_CLUSTERS.lock().unwrap().add(
+            rule.yaml["title"].as_str().unwrap_or(""),
+            &event_id,
+            &computer,
+            &output,
+            &timestamp,
+        );
+
         MESSAGES.lock().unwrap().insert(
             record_info.evtx_filepath.to_string(),
             rule.rulepath.to_string(),
             &record_info.record,
             rule.yaml["level"].as_str().unwrap_or("-").to_string(),
-            record_info.record["Event"]["System"]["Computer"]
-                .to_string()
-                .replace("\"", ""),
-            get_serde_number_to_string(&record_info.record["Event"]["System"]["EventID"])
-                .unwrap_or("-".to_owned())
-                .to_string(),
+            computer,
+            event_id,
             rule.yaml["title"].as_str().unwrap_or("").to_string(),
-            rule.yaml["output"].as_str().unwrap_or("").to_string(),
+            output,
         );
     }
 
@@ -280,3 +823,56 @@ fn test_parse_rule_files() {
     let cole = Detection::parse_rule_files(level.to_owned(), opt_rule_path, &filter::exclude_ids());
     assert_eq!(5, cole.len());
 }
+
+#[test]
+fn test_tokenize() {
+    let tokens = tokenize("Invoke-Mimikatz -DumpCreds");
+    assert!(tokens.contains("invoke"));
+    assert!(tokens.contains("mimikatz"));
+    assert!(tokens.contains("dumpcreds"));
+    // 英数字以外は区切りになり、空トークンは残らない。
+    assert!(!tokens.contains(""));
+}
+
+#[test]
+fn test_jaccard() {
+    let a: HashSet<String> = ["a", "b", "c"].iter().map(|s| s.to_string()).collect();
+    let b: HashSet<String> = ["b", "c", "d"].iter().map(|s| s.to_string()).collect();
+    // |A∩B| / |A∪B| = 2 / 4
+    assert!((jaccard(&a, &b) - 0.5).abs() < 1e-9);
+    assert!((jaccard(&a, &a) - 1.0).abs() < 1e-9);
+    // 両方空は0。
+    assert_eq!(0.0, jaccard(&HashSet::new(), &HashSet::new()));
+}
+
+#[test]
+fn test_alert_clusterer_add() {
+    let mut clusterer = AlertClusterer::new(0.7);
+    clusterer.add("Susp", "4104", "host1", "powershell download payload", "2023-01-01T00:00:00Z");
+    // ホストだけが異なるほぼ同一の検知は同じクラスタへ(Jaccard 5/7 ≒ 0.71 >= 0.7)。
+    clusterer.add("Susp", "4104", "host2", "powershell download payload", "2023-01-02T00:00:00Z");
+    assert_eq!(1, clusterer.clusters.len());
+    assert_eq!(2, clusterer.clusters[0].member_count);
+    assert_eq!(2, clusterer.clusters[0].hosts.len());
+    // 全く異なる検知は新規クラスタへ。
+    clusterer.add("Other", "1", "host3", "logon success interactive session", "2023-01-03T00:00:00Z");
+    assert_eq!(2, clusterer.clusters.len());
+    // resetで状態が空に戻る。
+    clusterer.reset();
+    assert!(clusterer.clusters.is_empty());
+}
+
+#[test]
+fn test_checkpoint_ingest() {
+    let mut checkpoint = Checkpoint::default();
+    checkpoint.ingest("a.evtx\trules/foo.yml\t1500");
+    checkpoint.ingest("b.evtx\trules/foo.yml\t42");
+    // 形式が合わない行(列数違い・数値でない)は無視される。
+    checkpoint.ingest("broken line");
+    checkpoint.ingest("c.evtx\trules/bar.yml\tNaN");
+    assert_eq!(1500, checkpoint.offset_of("a.evtx", "rules/foo.yml"));
+    assert_eq!(42, checkpoint.offset_of("b.evtx", "rules/foo.yml"));
+    // (file, rule) の組で引くので、ファイルが違えば0。
+    assert_eq!(0, checkpoint.offset_of("b.evtx", "rules/bar.yml"));
+    assert_eq!(0, checkpoint.offset_of("c.evtx", "rules/bar.yml"));
+}
@@ -1,16 +1,61 @@
 use crate::detections::utils;
 use crate::models::event;
+use crate::options::threat_intel;
+use base64::{engine::general_purpose::STANDARD, Engine};
 use regex::Regex;
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
 extern crate csv;
 
-pub struct PowerShell {}
+/// MessageNumber/MessageTotalで分割された4104スクリプトブロックを再結合するためのバッファ。
+/// ScriptBlockIdごとに、受信済みの (MessageNumber -> ScriptBlockText) を溜めておく。
+struct ScriptBlockAssembler {
+    total: usize,
+    fragments: HashMap<usize, String>,
+}
+
+impl ScriptBlockAssembler {
+    fn new(total: usize) -> ScriptBlockAssembler {
+        ScriptBlockAssembler {
+            total,
+            fragments: HashMap::new(),
+        }
+    }
+
+    /// フラグメントを取り込む。全パートが揃ったら番号順に連結した文字列を返す。
+    fn push(&mut self, number: usize, text: &str) -> Option<String> {
+        self.fragments.insert(number, text.to_string());
+        if self.fragments.len() < self.total {
+            return None;
+        }
+        let mut command = String::new();
+        for num in 1..=self.total {
+            if let Some(part) = self.fragments.get(&num) {
+                command.push_str(part);
+            }
+        }
+        Some(command)
+    }
+}
+
+// 同時に保持する未完成スクリプトブロックの上限。MessageTotalが永遠に揃わないブロックが
+// 溜まり続けてメモリをリークしないよう、この数を超えたら未完成分を破棄する。
+const MAX_PENDING_SCRIPTBLOCKS: usize = 1024;
+
+pub struct PowerShell {
+    // ScriptBlockIdごとの再結合バッファ。
+    assemblers: HashMap<String, ScriptBlockAssembler>,
+    // whitelist.txtはレコードごとに開き直さず、最初の参照時に一度だけ読み込んでキャッシュする。
+    whitelist: Option<String>,
+}
 
 impl PowerShell {
     pub fn new() -> PowerShell {
-        PowerShell {}
+        PowerShell {
+            assemblers: HashMap::new(),
+            whitelist: None,
+        }
     }
 
     pub fn detection(
@@ -26,6 +71,23 @@ impl PowerShell {
         }
     }
 
+    /// whitelist.txtの内容を一度だけ読み込み、以降はキャッシュを返す。
+    /// ファイルが無い場合はパニックせず空文字列として扱う(ホワイトリスト無しと同義)。
+    fn whitelist(&mut self) -> &str {
+        if self.whitelist.is_none() {
+            let contents = match File::open("whitelist.txt") {
+                Ok(mut f) => {
+                    let mut contents = String::new();
+                    let _ = f.read_to_string(&mut contents);
+                    contents
+                }
+                Err(_) => String::new(),
+            };
+            self.whitelist = Some(contents);
+        }
+        self.whitelist.as_deref().unwrap_or("")
+    }
+
     fn execute_pipeline(&mut self, event_data: &HashMap<String, String>) {
         // パイプライン実行をしています
         let default = String::from("");
@@ -41,12 +103,14 @@ impl PowerShell {
             let temp = rm_before.replace_all(commandline, "");
             let command = rm_after.replace_all(&temp, "");
 
-            let mut f = File::open("whitelist.txt").expect("file not found");
-            let mut contents = String::new();
-            let _ = f.read_to_string(&mut contents);
-
+            let contents = self.whitelist().to_string();
             let rdr = csv::Reader::from_reader(contents.as_bytes());
             if command != "" {
+                // 再構築したコマンド文字列を脅威インテリDBにも通し、一致したIOCを表示する。
+                let threat_labels = threat_intel::labels_for_text(&command);
+                if !threat_labels.is_empty() {
+                    println!("PowerShell ThreatIntel match: {}", threat_labels.join(", "));
+                }
                 utils::check_command(4103, &command, 1000, 0, &default, &default, rdr);
             }
         }
@@ -57,18 +121,141 @@ impl PowerShell {
         // リモートコマンドを実行します
         let default = String::from("");
         let message_num = event_data.get("MessageNumber");
-        let commandline = event_data.get("ScriptBlockText").unwrap_or(&default);
+        let raw = event_data.get("ScriptBlockText").unwrap_or(&default);
+
+        // MessageNumberが無い場合は従来どおり何もしない。
+        if message_num.is_none() {
+            return;
+        }
 
-        let mut f = File::open("whitelist.txt").expect("file not found");
-        let mut contents = String::new();
-        let _ = f.read_to_string(&mut contents);
+        // MessageNumber/MessageTotalで分割されたスクリプトブロックは、
+        // ScriptBlockIdをキーに全パートが揃うまで溜め込んでから連結する。
+        let total = event_data
+            .get("MessageTotal")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(1);
+        let number = message_num.and_then(|v| v.parse::<usize>().ok()).unwrap_or(1);
 
+        let command = if total <= 1 {
+            raw.to_string()
+        } else {
+            let id = event_data
+                .get("ScriptBlockId")
+                .cloned()
+                .unwrap_or_else(|| default.clone());
+            // 新しいスクリプトブロックを積む前に、未完成のものが上限を超えていたら破棄してメモリを抑える。
+            if !self.assemblers.contains_key(&id)
+                && self.assemblers.len() >= MAX_PENDING_SCRIPTBLOCKS
+            {
+                self.assemblers.clear();
+            }
+            let assembler = self
+                .assemblers
+                .entry(id.clone())
+                .or_insert_with(|| ScriptBlockAssembler::new(total));
+            match assembler.push(number, raw) {
+                // まだ全パートが揃っていないフラグメントは、ここでは判定しない。
+                None => return,
+                Some(joined) => {
+                    self.assemblers.remove(&id);
+                    joined
+                }
+            }
+        };
+
+        // 連結後のスクリプトブロックを難読化解除してから本来のコマンド文字列として扱う。
+        let command = deobfuscate(&command);
+
+        let contents = self.whitelist().to_string();
         let rdr = csv::Reader::from_reader(contents.as_bytes());
-        match message_num {
-            Some(_) => utils::check_command(4104, &commandline, 1000, 0, &default, &default, rdr),
-            _ => {}
+
+        // 再構築したコマンド文字列を脅威インテリDBにも通し、一致したIOCを表示する。
+        let threat_labels = threat_intel::labels_for_text(&command);
+        if !threat_labels.is_empty() {
+            println!("PowerShell ThreatIntel match: {}", threat_labels.join(", "));
         }
+        utils::check_command(4104, &command, 1000, 0, &default, &default, rdr);
 
         return;
     }
 }
+
+/// PowerShellの典型的な難読化を解いて、マッチャが本来のコマンド文字列を見られるようにする。
+/// - `-EncodedCommand`/`-enc` のbase64(UTF-16LE)ペイロードをデコードして展開する。
+/// - 単一引用符の文字列同士の`+`連結(`'a'+'b'`)を詰め、バッククォートを取り除く。
+///   フォーマット演算子(`-f`)による組み立ては評価しない。
+fn deobfuscate(command: &str) -> String {
+    let mut result = command.to_string();
+
+    // -EncodedCommand / -enc <base64> を復号し、該当セグメントだけをデコード結果に置き換える。
+    // エンコード済みセグメントが複数あればそれぞれをデコードし、コマンド行の他の部分はそのまま保持する。
+    let enc = Regex::new(r"(?i)-e(nc(odedcommand)?)?\s+([A-Za-z0-9+/=]+)").unwrap();
+    result = enc
+        .replace_all(&result, |caps: &regex::Captures| {
+            caps.get(3)
+                .and_then(|payload| decode_utf16le_base64(payload.as_str()))
+                // デコードできなければ元の文字列をそのまま残す。
+                .unwrap_or_else(|| caps[0].to_string())
+        })
+        .to_string();
+
+    // 単一引用符の文字列同士の`+`連結を詰める。'a'+'b' -> ab (フォーマット演算子 -f は扱わない)
+    let concat = Regex::new(r"'\s*\+\s*'").unwrap();
+    result = concat.replace_all(&result, "").to_string();
+
+    // バッククォートによるエスケープ難読化を除去する。
+    result = result.replace('`', "");
+
+    result
+}
+
+/// UTF-16LEでエンコードされたbase64ペイロードをデコードする。失敗時はNone。
+fn decode_utf16le_base64(payload: &str) -> Option<String> {
+    let bytes = STANDARD.decode(payload.as_bytes()).ok()?;
+    let units: Vec<u16> = bytes
+        .chunks_exact(2)
+        .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+        .collect();
+    String::from_utf16(&units).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script_block_assembler_push() {
+        let mut assembler = ScriptBlockAssembler::new(3);
+        // 全パートが揃うまではNone。受信順が前後しても番号順に連結する。
+        assert_eq!(assembler.push(1, "foo"), None);
+        assert_eq!(assembler.push(3, "baz"), None);
+        assert_eq!(assembler.push(2, "bar"), Some("foobarbaz".to_string()));
+    }
+
+    #[test]
+    fn test_decode_utf16le_base64() {
+        // "whoami" をUTF-16LEでbase64エンコードしたもの。
+        assert_eq!(
+            decode_utf16le_base64("dwBoAG8AYQBtAGkA"),
+            Some("whoami".to_string())
+        );
+        // 不正なbase64はNone。
+        assert_eq!(decode_utf16le_base64("!!!"), None);
+    }
+
+    #[test]
+    fn test_deobfuscate_encoded_command() {
+        // -encセグメントのみがデコードされ、コマンド行の他の部分は保持される。
+        let decoded = deobfuscate("powershell.exe -enc dwBoAG8AYQBtAGkA");
+        assert!(decoded.contains("powershell.exe"));
+        assert!(decoded.contains("whoami"));
+    }
+
+    #[test]
+    fn test_deobfuscate_concat_and_backtick() {
+        // 文字列連結の区切りを詰める。
+        assert!(deobfuscate("'ami'+'si'").contains("amisi"));
+        // バッククォートによる難読化を除去する。
+        assert_eq!(deobfuscate("w`h`o`a`m`i"), "whoami");
+    }
+}
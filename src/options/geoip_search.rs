@@ -1,7 +1,11 @@
 use compact_str::CompactString;
 use hashbrown::HashMap;
+use ip_network::IpNetwork;
+use ip_network_table::IpNetworkTable;
 use lazy_static::lazy_static;
 use maxminddb::{geoip2, MaxMindDBError, Reader};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use std::{net::IpAddr, str::FromStr};
@@ -9,15 +13,23 @@ use std::{net::IpAddr, str::FromStr};
 lazy_static! {
     pub static ref IP_MAP: Mutex<HashMap<IpAddr, CompactString>> = Mutex::new(HashMap::new());
 }
+
+// MaxMindのディレクトリから起動時に読み込む既定のブロックリストフィード名。
+const DEFAULT_BLOCKLIST_FILENAME: &str = "ip_blocklist.txt";
 pub struct GeoIPSearch {
     pub asn_reader: Reader<Vec<u8>>,
     pub country_reader: Reader<Vec<u8>>,
     pub city_reader: Reader<Vec<u8>>,
+    // CIDRレンジのブロックリスト/レピュテーションフィードを前方一致引きできるプレフィックス木に展開したもの。
+    // 値はそのレンジの出所(フィード名)。
+    blocklist: IpNetworkTable<CompactString>,
+    // refresh時に再読込するフィードのパス一覧。
+    blocklist_paths: Vec<PathBuf>,
 }
 
 impl GeoIPSearch {
     pub fn new(path: &Path, asn_country_city_filename: Vec<&str>) -> GeoIPSearch {
-        GeoIPSearch {
+        let mut geoip = GeoIPSearch {
             asn_reader: maxminddb::Reader::open_readfile(path.join(asn_country_city_filename[0]))
                 .unwrap(),
             country_reader: maxminddb::Reader::open_readfile(
@@ -26,7 +38,65 @@ impl GeoIPSearch {
             .unwrap(),
             city_reader: maxminddb::Reader::open_readfile(path.join(asn_country_city_filename[2]))
                 .unwrap(),
+            blocklist: IpNetworkTable::new(),
+            blocklist_paths: vec![],
+        };
+        // MaxMindのディレクトリに既定のブロックリストフィード(ip_blocklist.txt)があれば起動時に読み込む。
+        // 無ければブロックリストは空のまま(convert_ip_to_geoは照合マーカーを付けずに返す)。
+        let default_feed = path.join(DEFAULT_BLOCKLIST_FILENAME);
+        if default_feed.exists() {
+            geoip.load_blocklists(vec![default_feed]);
+        }
+        geoip
+    }
+
+    /// CIDRレンジのブロックリストフィードを読み込んで保持する。
+    /// 各行は "CIDR,source" 形式(出所が省略された場合は拡張子を除いたファイル名を出所として扱う)。
+    /// refresh_blocklistで再読込できるよう、パスも記録しておく。
+    pub fn load_blocklists(&mut self, paths: Vec<PathBuf>) {
+        self.blocklist_paths = paths;
+        self.blocklist = GeoIPSearch::build_blocklist(&self.blocklist_paths);
+    }
+
+    /// 記録済みのパスからフィードを読み直し、ブロックリストを差し替える。定期的なフィード更新に使う。
+    pub fn refresh_blocklist(&mut self) {
+        self.blocklist = GeoIPSearch::build_blocklist(&self.blocklist_paths);
+    }
+
+    fn build_blocklist(paths: &[PathBuf]) -> IpNetworkTable<CompactString> {
+        let mut table = IpNetworkTable::new();
+        for path in paths {
+            let default_source = path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .unwrap_or("blocklist")
+                .to_owned();
+            let file = match File::open(path) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            for line in BufReader::new(file).lines().flatten() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let mut fields = line.splitn(2, ',');
+                let cidr = fields.next().unwrap_or("").trim();
+                let source = fields.next().map(|s| s.trim()).unwrap_or(&default_source);
+                if let Ok(network) = IpNetwork::from_str_truncate(cidr) {
+                    table.insert(network, CompactString::from(source));
+                }
+            }
         }
+        return table;
+    }
+
+    /// 指定IPがブロックリストのいずれかのレンジに含まれるかを前方一致で調べ、含まれていればその出所を返す。
+    pub fn check_blocklist(&self, addr: &IpAddr) -> Option<CompactString> {
+        return self
+            .blocklist
+            .longest_match(*addr)
+            .map(|(_, source)| source.to_owned());
     }
 
     /// check existence files in specified path by geo-ip option.
@@ -58,20 +128,39 @@ impl GeoIPSearch {
     pub fn convert_ip_to_geo(&self, target_ip: &str) -> Result<String, MaxMindDBError> {
         let addr = IpAddr::from_str(target_ip).unwrap();
 
-        // If the IP address is the same, the result obtained is the same, so the lookup process is omitted by obtaining the result of a hit from the cache.
-        if let Some(cached_data) = IP_MAP.lock().unwrap().get(&addr) {
-            return Ok(cached_data.to_string());
-        }
+        // ASN/国/都市はIPが同じなら不変なのでキャッシュする。
+        // 一方でブロックリスト判定はフィードのrefreshで変わりうるため、キャッシュには含めず毎回引き直す。
+        // (マーカーをキャッシュ文字列に焼き込むと、refresh後も既存IPに反映されなくなってしまう)
+        let base = match IP_MAP.lock().unwrap().get(&addr) {
+            Some(cached_data) => Some(cached_data.to_string()),
+            None => None,
+        };
+        let base = match base {
+            Some(base) => base,
+            None => {
+                let asn: geoip2::Asn = self.asn_reader.lookup(addr)?;
+                let country: geoip2::Country = self.country_reader.lookup(addr)?;
+                let city: geoip2::City = self.city_reader.lookup(addr)?;
+                let base = format!("{asn:#?}🦅{country:#?}🦅{city:#?}");
+                IP_MAP
+                    .lock()
+                    .unwrap()
+                    .insert(addr, CompactString::from(&base));
+                base
+            }
+        };
 
-        let asn: geoip2::Asn = self.asn_reader.lookup(addr)?;
-        let country: geoip2::Country = self.country_reader.lookup(addr)?;
-        let city: geoip2::City = self.city_reader.lookup(addr)?;
-        let geo_data = format!("{asn:#?}🦅{country:#?}🦅{city:#?}");
-        IP_MAP
-            .lock()
-            .unwrap()
-            .insert(addr, CompactString::from(&geo_data));
-        Ok(geo_data)
+        // ブロックリストフィードを読み込んでいる場合のみ、照合結果を付与する。
+        // フィードが無い通常スキャンでは3セグメントのまま(🦅で分割する既存コードの前提を崩さない)。
+        let (v4_count, v6_count) = self.blocklist.len();
+        if v4_count == 0 && v6_count == 0 {
+            return Ok(base);
+        }
+        let blocklist_mark = match self.check_blocklist(&addr) {
+            Some(source) => format!("listed({source})"),
+            None => "not-listed".to_owned(),
+        };
+        Ok(format!("{base}🦅{blocklist_mark}"))
     }
 }
 
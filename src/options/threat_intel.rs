@@ -0,0 +1,225 @@
+use compact_str::CompactString;
+use hashbrown::{HashMap, HashSet};
+use lazy_static::lazy_static;
+use regex::{Regex, RegexSet};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+lazy_static! {
+    // ロード済みの脅威インテリジェンスDB。geoip_searchのIP_MAPと同様にグローバルなMutexで保持する。
+    pub static ref THREAT_INTEL: Mutex<ThreatIntel> = Mutex::new(ThreatIntel::default());
+}
+
+// 脅威インテリのインジケータフィードを置く既定ディレクトリ。
+const DIRPATH_THREAT_INTEL: &str = "config/threat_intel";
+
+/// IOCに一致したときにアラートへ付与するラベル。(脅威カテゴリ + 深刻度)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreatLabel {
+    pub category: CompactString,
+    pub severity: CompactString,
+}
+
+impl ThreatLabel {
+    fn new(category: &str, severity: &str) -> ThreatLabel {
+        ThreatLabel {
+            category: CompactString::from(category),
+            severity: CompactString::from(severity),
+        }
+    }
+
+    /// 出力スタックに積む際の表記。"category(severity)" 形式。
+    pub fn to_label(&self) -> CompactString {
+        CompactString::from(format!("{}({})", self.category, self.severity))
+    }
+}
+
+/// 悪性IP・ドメイン・ファイルハッシュ・既知の不正コマンドパターンのインジケータをまとめた in-memory DB。
+/// 外部のtidb的なインジケータDBをそのままルールYAMLと並行してイベントに適用するためのもの。
+#[derive(Default)]
+pub struct ThreatIntel {
+    // IpAddr -> ラベル。geoip_searchのIP_MAPを鏡写しにした構造。
+    ip_map: HashMap<IpAddr, ThreatLabel>,
+    // 小文字化したファイルハッシュの集合。ラベルは一律 malware-hash/high とする。
+    hashes: HashSet<CompactString>,
+    // 小文字化したドメインの集合。
+    domains: HashSet<CompactString>,
+    // 不正コマンドパターンをまとめてコンパイルしたRegexSet。
+    command_patterns: RegexSet,
+    // command_patternsの各パターンに対応するラベル(インデックス対応)。
+    command_labels: Vec<ThreatLabel>,
+}
+
+impl ThreatIntel {
+    /// 指定ディレクトリからインジケータフィードを読み込む。
+    /// ips.csv / domains.txt / hashes.txt / commands.csv を想定し、無いファイルは単に無視する。
+    /// csv行は "indicator,category,severity" 形式、txt行は1行1インジケータとして扱う。
+    pub fn load(dir: &Path) -> ThreatIntel {
+        let mut intel = ThreatIntel::default();
+
+        for line in read_lines(&dir.join("ips.csv")) {
+            let fields: Vec<&str> = line.split(',').collect();
+            if let Ok(ip) = IpAddr::from_str(fields[0].trim()) {
+                intel.ip_map.insert(ip, label_from_fields(&fields));
+            }
+        }
+
+        for line in read_lines(&dir.join("domains.txt")) {
+            let domain = line.trim();
+            if !domain.is_empty() {
+                intel.domains.insert(CompactString::from(domain.to_lowercase()));
+            }
+        }
+
+        for line in read_lines(&dir.join("hashes.txt")) {
+            let hash = line.trim();
+            if !hash.is_empty() {
+                intel.hashes.insert(CompactString::from(hash.to_lowercase()));
+            }
+        }
+
+        let mut patterns = vec![];
+        for line in read_lines(&dir.join("commands.csv")) {
+            let fields: Vec<&str> = line.split(',').collect();
+            let pattern = fields[0].trim();
+            if pattern.is_empty() {
+                continue;
+            }
+            // 不正な正規表現は1行ずつ個別に検証してスキップし、残りのパターンは生かす。
+            // (RegexSet::newに一括で渡すと1行の誤りで全体のコンパイルが失敗してしまうため)
+            if Regex::new(pattern).is_err() {
+                continue;
+            }
+            patterns.push(pattern.to_string());
+            intel.command_labels.push(label_from_fields(&fields));
+        }
+        // 各パターンは個別に検証済みなのでRegexSet::newは通常成功する。万一失敗した場合のみ空集合にフォールバックする。
+        intel.command_patterns = RegexSet::new(&patterns).unwrap_or_else(|_| {
+            intel.command_labels.clear();
+            RegexSet::empty()
+        });
+
+        return intel;
+    }
+
+    pub fn match_ip(&self, ip: &IpAddr) -> Option<&ThreatLabel> {
+        return self.ip_map.get(ip);
+    }
+
+    pub fn match_hash(&self, hash: &str) -> bool {
+        return self.hashes.contains(hash.to_lowercase().as_str());
+    }
+
+    pub fn match_domain(&self, domain: &str) -> bool {
+        return self.domains.contains(domain.to_lowercase().as_str());
+    }
+
+    /// コマンド文字列をRegexSetに通し、一致した全パターンのラベルを返す。
+    pub fn match_command(&self, command: &str) -> Vec<ThreatLabel> {
+        return self
+            .command_patterns
+            .matches(command)
+            .into_iter()
+            .filter_map(|idx| self.command_labels.get(idx).cloned())
+            .collect();
+    }
+
+    /// レコード本文やコマンド文字列などの任意のテキストに対し、一致したIOCのラベルを集める。
+    /// IP・ドメイン・ハッシュはトークン境界で照合し、コマンドは正規表現一致で判定する。
+    pub fn labels_for_text(&self, text: &str) -> Vec<ThreatLabel> {
+        let mut labels = self.match_command(text);
+
+        // 悪性IP: テキスト中のIPらしきトークンを取り出してip_mapと照合する。
+        if !self.ip_map.is_empty() {
+            for token in text.split(|c: char| !(c.is_ascii_hexdigit() || c == '.' || c == ':')) {
+                if let Ok(ip) = IpAddr::from_str(token) {
+                    if let Some(label) = self.match_ip(&ip) {
+                        labels.push(label.clone());
+                    }
+                }
+            }
+        }
+
+        // ドメイン: 部分文字列ではなくホスト境界で照合する。
+        // トークン全体が一致するか、".domain" で終わる(サブドメイン)場合のみヒットとし、notevil.com が evil.com に一致しないようにする。
+        if !self.domains.is_empty() {
+            for token in text.split(|c: char| !(c.is_alphanumeric() || c == '.' || c == '-')) {
+                if token.is_empty() {
+                    continue;
+                }
+                let host = token.to_lowercase();
+                for domain in &self.domains {
+                    if host == domain.as_str() || host.ends_with(&format!(".{domain}")) {
+                        labels.push(ThreatLabel::new("c2-domain", "high"));
+                        break;
+                    }
+                }
+            }
+        }
+
+        // ハッシュ: 任意文字列の部分一致ではなく、抽出した16進トークン(長さ32/40/64)との完全一致で照合する。
+        if !self.hashes.is_empty() {
+            for token in text.split(|c: char| !c.is_ascii_hexdigit()) {
+                if matches!(token.len(), 32 | 40 | 64)
+                    && self.hashes.contains(token.to_lowercase().as_str())
+                {
+                    labels.push(ThreatLabel::new("malware-hash", "high"));
+                }
+            }
+        }
+
+        return labels;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.ip_map.is_empty()
+            && self.hashes.is_empty()
+            && self.domains.is_empty()
+            && self.command_labels.is_empty();
+    }
+}
+
+/// 指定ディレクトリからロードした脅威インテリDBをグローバルのTHREAT_INTELへ差し込む。
+/// ロード結果を返すだけでは誰もグローバルに格納しないため、起動時にこの関数を呼んで有効化する。
+pub fn install(dir: &Path) {
+    *THREAT_INTEL.lock().unwrap() = ThreatIntel::load(dir);
+}
+
+/// 既定ディレクトリ(config/threat_intel)からフィードを読み込んで有効化する。起動時に一度呼ぶ。
+/// フィードが無ければloadは空のDBを返すため、脅威インテリを使わない通常スキャンには影響しない。
+pub fn install_default() {
+    install(Path::new(DIRPATH_THREAT_INTEL));
+}
+
+/// グローバルな脅威インテリDBに問い合わせ、一致したラベルを "category(severity)" 表記で返す。
+/// DBが未ロードの場合は空を返すため、脅威インテリを使わない通常スキャンに影響しない。
+pub fn labels_for_text(text: &str) -> Vec<CompactString> {
+    let intel = THREAT_INTEL.lock().unwrap();
+    if intel.is_empty() {
+        return vec![];
+    }
+    return intel
+        .labels_for_text(text)
+        .iter()
+        .map(ThreatLabel::to_label)
+        .collect();
+}
+
+// "indicator,category,severity" の2,3列目からラベルを作る。欠けている場合は既定値で補う。
+fn label_from_fields(fields: &[&str]) -> ThreatLabel {
+    let category = fields.get(1).map(|s| s.trim()).unwrap_or("malicious");
+    let severity = fields.get(2).map(|s| s.trim()).unwrap_or("high");
+    return ThreatLabel::new(category, severity);
+}
+
+// ファイルを1行ずつ読む。開けない場合は空のイテレータ相当(空Vec)を返す。
+fn read_lines(path: &Path) -> Vec<String> {
+    match File::open(path) {
+        Ok(file) => BufReader::new(file).lines().flatten().collect(),
+        Err(_) => vec![],
+    }
+}